@@ -0,0 +1,26 @@
+use std::fmt;
+use std::result;
+
+/// Errors that can be returned from the public API.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// Adding an entry to a size-bounded `WriteBatch` would have exceeded its configured
+    /// capacity (in bytes).
+    WriteBatchFull(usize),
+    /// A serialized on-disk/on-wire structure (e.g. a `WriteBatch` rebuilt from `from_data()`)
+    /// failed to parse because its bytes are truncated or otherwise malformed.
+    Corruption(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::WriteBatchFull(cap) => {
+                write!(f, "write batch exceeded its capacity of {} bytes", cap)
+            }
+            Error::Corruption(ref msg) => write!(f, "corruption: {}", msg),
+        }
+    }
+}
@@ -1,77 +1,147 @@
+use error::{Error, Result};
 use memtable::MemTable;
 use types::{Comparator, SequenceNumber, ValueType};
-use integer_encoding::VarInt;
-
-struct BatchEntry<'a> {
-    key: &'a [u8],
-    // None => value type is delete, Some(x) => value type is add
-    val: Option<&'a [u8]>,
+use integer_encoding::{FixedInt, VarInt};
+
+/// A WriteBatch bundles multiple `put`/`delete` operations into a single atomic record that can
+/// be serialized to (and replayed from) the write-ahead log.
+///
+/// The batch is kept as a flat, self-describing byte buffer rather than as a list of borrowed
+/// key/value slices. This avoids lifetime parameters on `WriteBatch`, and makes the in-memory
+/// representation identical to the on-disk/on-wire representation: a 12-byte header followed by
+/// the entries.
+///
+/// Header layout (12 bytes):
+///
+/// * bytes `0..8`: base `SequenceNumber` of the batch, little-endian fixed-size encoding.
+/// * bytes `8..12`: number of entries in the batch, little-endian fixed-size `u32`.
+///
+/// Each entry is encoded as `[tag: 1][keylen: varint][key][vallen: varint][value]`, where a
+/// `TypeDeletion` tag omits the value length and value.
+///
+/// # Durability is not yet enforced
+///
+/// `set_sync()`/`sync()` record a per-batch request for a durable (fsync'd) log write, but this
+/// crate has no log writer yet, so nothing acts on that flag beyond `sync()` reporting it and
+/// `append()` carrying it over to the merged batch. `set_sync(true)` today is
+/// plumbing for a write path that doesn't exist here: it is not a durability guarantee, and
+/// callers must not rely on it as one until a log writer in this crate actually consumes it.
+pub struct WriteBatch {
+    entries: Vec<u8>,
+    /// Whether a future log writer should force a flush (fsync) of this batch, independent of
+    /// the database's global `Options::sync` setting. See the `WriteBatch` docs: only `sync()`
+    /// and `append()` read this field; no write path in this crate does.
+    sync: bool,
+    /// Optional cap on `entries.len()`, in bytes. `put`/`delete` refuse to grow the batch past
+    /// this budget, returning `Error::WriteBatchFull` instead. Used to bound WAL record size in
+    /// memory-constrained/enclave deployments.
+    max_size: Option<usize>,
 }
 
-pub struct WriteBatch<'a> {
-    entries: Vec<BatchEntry<'a>>,
-    seq: SequenceNumber,
-}
+const HEADER_SIZE: usize = 8 + 4;
 
-impl<'a> WriteBatch<'a> {
-    fn new(seq: SequenceNumber) -> WriteBatch<'a> {
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        let mut entries = Vec::with_capacity(HEADER_SIZE);
+        entries.resize(HEADER_SIZE, 0);
         WriteBatch {
-            entries: Vec::new(),
-            seq: seq,
+            entries: entries,
+            sync: false,
+            max_size: None,
         }
     }
 
-    fn with_capacity(seq: SequenceNumber, c: usize) -> WriteBatch<'a> {
+    pub fn with_capacity(c: usize) -> WriteBatch {
+        let mut entries = Vec::with_capacity(HEADER_SIZE + c);
+        entries.resize(HEADER_SIZE, 0);
         WriteBatch {
-            entries: Vec::with_capacity(c),
-            seq: seq,
+            entries: entries,
+            sync: false,
+            max_size: None,
         }
     }
 
-    fn put(&mut self, k: &'a [u8], v: &'a [u8]) {
-        self.entries.push(BatchEntry {
-            key: k,
-            val: Some(v),
-        })
+    /// Like `new()`, but rejects entries once the serialized batch would grow past `max_size`
+    /// bytes (header included), rather than growing without bound.
+    pub fn with_max_size(max_size: usize) -> WriteBatch {
+        let mut b = WriteBatch::new();
+        b.max_size = Some(max_size);
+        b
     }
 
-    fn delete(&mut self, k: &'a [u8]) {
-        self.entries.push(BatchEntry {
-            key: k,
-            val: None,
-        })
+    /// Records a request that this batch be committed with a durable (fsync'd) log write,
+    /// regardless of the database's global sync setting. See the "Durability is not yet
+    /// enforced" note on `WriteBatch`: no log writer in this crate reads this flag, so setting
+    /// it does not make any write durable.
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
     }
 
-    fn clear(&mut self) {
-        self.entries.clear()
+    /// Whether this batch was marked via `set_sync()` (directly, or through `append()` of a
+    /// marked batch) as requiring a durable log write. Not enforced by anything in this crate.
+    pub fn sync(&self) -> bool {
+        self.sync
     }
 
-    fn byte_size(&self) -> usize {
-        let mut size = 0;
+    /// Replaces the contents of this batch with a previously-serialized batch, as returned by
+    /// `data()`. `data` is untrusted (it may come from a replica or a WAL record being
+    /// recovered), so it is fully validated before being adopted; malformed input leaves this
+    /// batch unchanged and returns `Error::Corruption`.
+    pub fn set_contents(&mut self, data: &[u8]) -> Result<()> {
+        validate(data)?;
+        self.entries.clear();
+        self.entries.extend_from_slice(data);
+        Ok(())
+    }
 
-        for e in self.entries.iter() {
-            size += e.key.len() + e.key.len().required_space();
+    pub fn put(&mut self, k: &[u8], v: &[u8]) -> Result<()> {
+        self.check_budget(k, Some(v))?;
+        self.append_entry(ValueType::TypeValue, k, Some(v));
+        Ok(())
+    }
 
-            if let Some(v) = e.val {
-                size += v.len() + v.len().required_space();
-            } else {
-                size += 1;
+    pub fn delete(&mut self, k: &[u8]) -> Result<()> {
+        self.check_budget(k, None)?;
+        self.append_entry(ValueType::TypeDeletion, k, None);
+        Ok(())
+    }
+
+    /// Computes the prospective size of adding `(k, v)`, as in `byte_size()`, and fails with
+    /// `Error::WriteBatchFull` if that would exceed this batch's configured byte budget.
+    fn check_budget(&self, k: &[u8], v: Option<&[u8]>) -> Result<()> {
+        if let Some(max_size) = self.max_size {
+            let mut added = 1 + k.len() + k.len().required_space();
+            if let Some(v) = v {
+                added += v.len() + v.len().required_space();
             }
 
-            size += 1; // account for tag
+            if self.byte_size() + added > max_size {
+                return Err(Error::WriteBatchFull(max_size));
+            }
         }
-        size
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        let seq = self.sequence();
+        self.entries.clear();
+        self.entries.resize(HEADER_SIZE, 0);
+        self.set_sequence(seq);
+    }
+
+    pub fn byte_size(&self) -> usize {
+        self.entries.len()
     }
 
-    fn iter<'b>(&'b self) -> WriteBatchIter<'b, 'a> {
+    pub fn iter<'a>(&'a self) -> WriteBatchIter<'a> {
         WriteBatchIter {
             batch: self,
-            ix: 0,
+            ix: HEADER_SIZE,
         }
     }
 
-    fn insert_into_memtable<C: Comparator>(&self, mt: &mut MemTable<C>) {
-        let mut sequence_num = self.seq;
+    pub fn insert_into_memtable<C: Comparator>(&self, mt: &mut MemTable<C>) {
+        let mut sequence_num = self.sequence();
 
         for (k, v) in self.iter() {
             match v {
@@ -82,63 +152,197 @@ impl<'a> WriteBatch<'a> {
         }
     }
 
-    fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(self.byte_size());
-        let mut ix = 0;
+    pub fn sequence(&self) -> SequenceNumber {
+        SequenceNumber::decode_fixed(&self.entries[0..8])
+    }
 
-        for (k, v) in self.iter() {
-            if let Some(_) = v {
-                buf.push(ValueType::TypeValue as u8);
-            } else {
-                buf.push(ValueType::TypeDeletion as u8);
+    pub fn set_sequence(&mut self, seq: SequenceNumber) {
+        seq.encode_fixed(&mut self.entries[0..8]);
+    }
+
+    /// Appends `other`'s entries onto this batch, operating directly on the serialized buffers
+    /// (skipping `other`'s header and copying its entry region), and bumps this batch's entry
+    /// count by `other.count()`. Useful for merging several independently-built sub-batches
+    /// (e.g. gathered from multiple worker threads) into one atomic commit.
+    ///
+    /// Fails with `Error::WriteBatchFull` (leaving this batch unchanged) if appending `other`
+    /// would grow this batch past its configured `max_size`. If `other` was marked via
+    /// `set_sync()`, this batch is marked too, so merging in a sub-batch that needed a durable
+    /// write doesn't silently downgrade it.
+    pub fn append(&mut self, other: &WriteBatch) -> Result<()> {
+        let added = other.entries.len() - HEADER_SIZE;
+
+        if let Some(max_size) = self.max_size {
+            if self.byte_size() + added > max_size {
+                return Err(Error::WriteBatchFull(max_size));
             }
+        }
 
-            ix += 1;
+        self.entries.extend_from_slice(&other.entries[HEADER_SIZE..]);
+        self.sync = self.sync || other.sync();
 
-            let req = k.len().required_space();
-            buf.resize(ix + req, 0);
-            ix += k.len().encode_var(&mut buf[ix..ix + req]);
+        let count = self.count() + other.count();
+        self.set_count(count);
+        Ok(())
+    }
 
-            buf.extend_from_slice(k);
-            ix += k.len();
+    /// Returns the raw on-wire representation of this batch (header followed by entries), as
+    /// used by `from_data()` and suitable for writing verbatim to the log or shipping to a
+    /// replica.
+    pub fn data(&self) -> &[u8] {
+        &self.entries
+    }
 
-            let req2;
-            let v_;
+    /// Reconstructs a batch from bytes previously returned by `data()`, without copying the
+    /// individual keys/values out of the buffer. Returns `Error::Corruption` if `data` is not a
+    /// well-formed serialized batch.
+    pub fn from_data(data: &[u8]) -> Result<WriteBatch> {
+        let mut b = WriteBatch::new();
+        b.set_contents(data)?;
+        Ok(b)
+    }
 
-            if let Some(v__) = v {
-                v_ = v__;
-                req2 = v_.len().required_space();
-            } else {
-                v_ = "".as_bytes();
-                req2 = 0.required_space();
+    /// Number of entries (`put`/`delete` operations) contained in this batch.
+    pub fn count(&self) -> u32 {
+        u32::decode_fixed(&self.entries[8..HEADER_SIZE])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    fn set_count(&mut self, c: u32) {
+        c.encode_fixed(&mut self.entries[8..HEADER_SIZE]);
+    }
+
+    fn append_entry(&mut self, t: ValueType, k: &[u8], v: Option<&[u8]>) {
+        self.entries.push(t as u8);
+
+        let mut buf = [0 as u8; 8];
+
+        let klen = k.len().encode_var(&mut buf);
+        self.entries.extend_from_slice(&buf[0..klen]);
+        self.entries.extend_from_slice(k);
+
+        if let Some(v) = v {
+            let vlen = v.len().encode_var(&mut buf);
+            self.entries.extend_from_slice(&buf[0..vlen]);
+            self.entries.extend_from_slice(v);
+        }
+
+        let count = self.count();
+        self.set_count(count + 1);
+    }
+}
+
+/// Walks `data` entry by entry, bounds-checking every tag/length/payload against `data.len()`
+/// and cross-checking the header's entry count, without trusting any of it. Used to validate
+/// buffers coming from outside this process (`set_contents()`/`from_data()`) before they are
+/// iterated or indexed into.
+fn validate(data: &[u8]) -> Result<()> {
+    if data.len() < HEADER_SIZE {
+        return Err(Error::Corruption("write batch shorter than its header".to_string()));
+    }
+
+    let mut ix = HEADER_SIZE;
+    let mut seen: u32 = 0;
+
+    while ix < data.len() {
+        let tag = data[ix];
+        ix += 1;
+
+        if tag != ValueType::TypeValue as u8 && tag != ValueType::TypeDeletion as u8 {
+            return Err(Error::Corruption(format!("write batch entry has unknown tag {}", tag)));
+        }
+
+        let (klen, klen_size) = read_varint(&data[ix..])
+            .ok_or_else(|| Error::Corruption("write batch key length is truncated".to_string()))?;
+        ix += klen_size;
+        if klen > data.len() - ix {
+            return Err(Error::Corruption("write batch key is truncated".to_string()));
+        }
+        ix += klen;
+
+        if tag == ValueType::TypeValue as u8 {
+            let (vlen, vlen_size) = read_varint(&data[ix..])
+                .ok_or_else(|| Error::Corruption("write batch value length is truncated".to_string()))?;
+            ix += vlen_size;
+            if vlen > data.len() - ix {
+                return Err(Error::Corruption("write batch value is truncated".to_string()));
             }
+            ix += vlen;
+        }
 
-            buf.resize(ix + req2, 0);
-            ix += v_.len().encode_var(&mut buf[ix..ix + req2]);
+        seen += 1;
+    }
+
+    let count = u32::decode_fixed(&data[8..HEADER_SIZE]);
+    if count != seen {
+        return Err(Error::Corruption(format!("write batch header claims {} entries, found {}",
+                                              count,
+                                              seen)));
+    }
+
+    Ok(())
+}
+
+/// A minimal, panic-free LEB128 varint reader used only by `validate()`. Unlike
+/// `usize::decode_var()`, it never reads past the end of `data`.
+fn read_varint(data: &[u8]) -> Option<(usize, usize)> {
+    let mut result: u64 = 0;
+    let mut shift = 0;
+    let mut i = 0;
 
-            buf.extend_from_slice(v_);
-            ix += v_.len();
+    loop {
+        let byte = *data.get(i)?;
+        i += 1;
+
+        result |= ((byte & 0x7f) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Some((result as usize, i));
+        }
+
+        shift += 7;
+        if shift >= 64 {
+            return None;
         }
-        buf
     }
 }
 
-pub struct WriteBatchIter<'b, 'a: 'b> {
-    batch: &'b WriteBatch<'a>,
+pub struct WriteBatchIter<'a> {
+    batch: &'a WriteBatch,
     ix: usize,
 }
 
-/// `'b` is the lifetime of the WriteBatch; `'a` is the lifetime of the slices contained in the
-/// batch.
-impl<'b, 'a: 'b> Iterator for WriteBatchIter<'b, 'a> {
+impl<'a> Iterator for WriteBatchIter<'a> {
     type Item = (&'a [u8], Option<&'a [u8]>);
     fn next(&mut self) -> Option<Self::Item> {
-        if self.ix < self.batch.entries.len() {
-            self.ix += 1;
-            Some((self.batch.entries[self.ix - 1].key, self.batch.entries[self.ix - 1].val))
-        } else {
-            None
+        let buf = &self.batch.entries;
+
+        if self.ix >= buf.len() {
+            return None;
         }
+
+        let tag = buf[self.ix];
+        self.ix += 1;
+
+        let (klen, klen_size) = usize::decode_var(&buf[self.ix..]);
+        self.ix += klen_size;
+        let key = &buf[self.ix..self.ix + klen];
+        self.ix += klen;
+
+        let val = if tag == ValueType::TypeDeletion as u8 {
+            None
+        } else {
+            let (vlen, vlen_size) = usize::decode_var(&buf[self.ix..]);
+            self.ix += vlen_size;
+            let value = &buf[self.ix..self.ix + vlen];
+            self.ix += vlen;
+            Some(value)
+        };
+
+        Some((key, val))
     }
 }
 
@@ -149,7 +353,7 @@ mod tests {
 
     #[test]
     fn test_write_batch() {
-        let mut b = WriteBatch::with_capacity(1, 16);
+        let mut b = WriteBatch::with_capacity(32);
         let entries = vec![("abc".as_bytes(), "def".as_bytes()),
                            ("123".as_bytes(), "456".as_bytes()),
                            ("xxx".as_bytes(), "yyy".as_bytes()),
@@ -158,14 +362,13 @@ mod tests {
 
         for &(k, v) in entries.iter() {
             if !v.is_empty() {
-                b.put(k, v);
+                b.put(k, v).unwrap();
             } else {
-                b.delete(k)
+                b.delete(k).unwrap()
             }
         }
 
-        assert_eq!(b.byte_size(), 39);
-        assert_eq!(b.encode().len(), 39);
+        assert_eq!(b.byte_size(), HEADER_SIZE + 37);
         assert_eq!(b.iter().count(), 5);
 
         let mut i = 0;
@@ -181,4 +384,93 @@ mod tests {
             i += 1;
         }
     }
+
+    #[test]
+    fn test_write_batch_data_roundtrip() {
+        let mut b = WriteBatch::new();
+        b.set_sequence(42);
+        b.put("abc".as_bytes(), "def".as_bytes()).unwrap();
+        b.delete("xyz".as_bytes()).unwrap();
+
+        let b2 = WriteBatch::from_data(b.data()).unwrap();
+
+        assert_eq!(b2.sequence(), 42);
+        assert_eq!(b2.count(), 2);
+        assert!(!b2.is_empty());
+        assert_eq!(b2.iter().collect::<Vec<_>>(), b.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_write_batch_from_data_rejects_corrupt_input() {
+        let mut b = WriteBatch::new();
+        b.put("abc".as_bytes(), "def".as_bytes()).unwrap();
+
+        // Truncate the serialized buffer mid-entry, cutting off the value.
+        let mut truncated = b.data().to_vec();
+        truncated.truncate(truncated.len() - 2);
+
+        assert!(WriteBatch::from_data(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_write_batch_set_sync() {
+        let mut b = WriteBatch::new();
+        assert!(!b.sync());
+        b.set_sync(true);
+        assert!(b.sync());
+    }
+
+    #[test]
+    fn test_write_batch_full() {
+        let max_size = HEADER_SIZE + 9;
+        let mut b = WriteBatch::with_max_size(max_size);
+
+        b.put("abc".as_bytes(), "def".as_bytes()).unwrap();
+        assert_eq!(b.put("abc".as_bytes(), "def".as_bytes()),
+                   Err(Error::WriteBatchFull(max_size)));
+    }
+
+    #[test]
+    fn test_write_batch_full_delete_exact_fit() {
+        // tag(1) + keylen varint(1) + key(2) == 4 bytes; a delete omits the vallen varint
+        // entirely, so this must fit exactly rather than being rejected as one byte too big.
+        let max_size = HEADER_SIZE + 4;
+        let mut b = WriteBatch::with_max_size(max_size);
+
+        b.delete("ab".as_bytes()).unwrap();
+        assert_eq!(b.byte_size(), max_size);
+    }
+
+    #[test]
+    fn test_write_batch_append() {
+        let mut a = WriteBatch::new();
+        a.put("abc".as_bytes(), "def".as_bytes()).unwrap();
+
+        let mut b = WriteBatch::new();
+        b.put("123".as_bytes(), "456".as_bytes()).unwrap();
+        b.delete("xyz".as_bytes()).unwrap();
+        b.set_sync(true);
+
+        a.append(&b).unwrap();
+
+        assert_eq!(a.count(), 3);
+        assert!(a.sync());
+        assert_eq!(a.iter().collect::<Vec<_>>(),
+                   vec![("abc".as_bytes(), Some("def".as_bytes())),
+                        ("123".as_bytes(), Some("456".as_bytes())),
+                        ("xyz".as_bytes(), None)]);
+    }
+
+    #[test]
+    fn test_write_batch_append_respects_max_size() {
+        let max_size = HEADER_SIZE + 9;
+        let mut a = WriteBatch::with_max_size(max_size);
+        a.put("abc".as_bytes(), "def".as_bytes()).unwrap();
+
+        let mut b = WriteBatch::new();
+        b.put("123".as_bytes(), "456".as_bytes()).unwrap();
+
+        assert_eq!(a.append(&b), Err(Error::WriteBatchFull(max_size)));
+        assert_eq!(a.count(), 1);
+    }
 }